@@ -8,36 +8,187 @@ lazy_static!{
     static ref INSTRUCTION_REGEX: Regex = Regex::new(r"(?P<opcode>(nop|acc|jmp))\s(?P<arg>[+-]\d+)").expect("illegal instruction regex");
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 enum Instruction {
     Nop(i64),
     Acc(i64),
     Jmp(i64),
 }
 
+impl Instruction {
+    /// Number of cycles the instruction occupies while executing.
+    fn cycles(&self) -> usize {
+        match self {
+            Instruction::Nop(_) => 1,
+            Instruction::Acc(_) => 2,
+            Instruction::Jmp(_) => 3,
+        }
+    }
+}
+
 type Program = Vec<Instruction>;
 
-fn flip_after(current: &[Instruction], n: usize) -> (usize, Program) {
-    let mut new_program = current.to_vec();
-    for i in n..new_program.len() {
-        match new_program.get(i).expect("instruction out of range") {
-            Instruction::Nop(n) => {
-                new_program[i] = Instruction::Jmp(*n);
-                return (i+1, new_program);
-            },
-            Instruction::Jmp(n) => {
-                new_program[i] = Instruction::Nop(*n);
-                return (i + 1, new_program);
-            },
-            _ => (),
+/// Indexed, read-only access to a sequence of instructions, so the VM can run
+/// over a real `Program` or a cheap overlay without copying either.
+trait InstructionSource {
+    fn get(&self, i: usize) -> Instruction;
+    fn len(&self) -> usize;
+}
+
+impl InstructionSource for [Instruction] {
+    fn get(&self, i: usize) -> Instruction {
+        self[i]
+    }
+
+    fn len(&self) -> usize {
+        <[Instruction]>::len(self)
+    }
+}
+
+/// A borrowed view over a program with at most one instruction rewritten to its
+/// jmp↔nop flipped form, yielded lazily so candidate programs never allocate.
+struct PatchedProgram<'a> {
+    program: &'a [Instruction],
+    override_index: Option<usize>,
+}
+
+impl<'a> InstructionSource for PatchedProgram<'a> {
+    fn get(&self, i: usize) -> Instruction {
+        let instruction = self.program[i];
+        if self.override_index == Some(i) {
+            match instruction {
+                Instruction::Nop(n) => Instruction::Jmp(n),
+                Instruction::Jmp(n) => Instruction::Nop(n),
+                other => other,
+            }
+        } else {
+            instruction
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.program.len()
+    }
+}
+
+#[derive(Debug)]
+enum ExecError {
+    InfiniteLoop { accumulator: i64 },
+    SegmentationFault { pc: usize },
+}
+
+#[derive(Debug)]
+enum ParseError {
+    InvalidInstruction(String),
+    InvalidArgument(String),
+}
+
+/// The result of advancing the `Computer` a single instruction.
+#[derive(Debug)]
+enum StepOutcome {
+    Continue,
+    Halt(i64),
+    LoopDetected(i64),
+}
+
+/// A record of one executed step, suitable for disassembly-style tracing.
+#[derive(Debug)]
+struct TraceRecord {
+    pc_before: usize,
+    pc_after: usize,
+    instruction: Instruction,
+    accumulator_delta: i64,
+    target_visited: bool,
+}
+
+fn normal_successor(i: usize, instruction: &Instruction, len: usize) -> Option<usize> {
+    match instruction {
+        Instruction::Jmp(n) => {
+            let target = i as i64 + *n;
+            if target >= 0 && target as usize <= len {
+                Some(target as usize)
+            } else {
+                None
+            }
+        }
+        _ => Some(i + 1),
+    }
+}
+
+fn flipped_successor(i: usize, instruction: &Instruction, len: usize) -> Option<usize> {
+    match instruction {
+        Instruction::Nop(n) => {
+            let target = i as i64 + *n;
+            if target >= 0 && target as usize <= len {
+                Some(target as usize)
+            } else {
+                None
+            }
         }
+        Instruction::Jmp(_) => Some(i + 1),
+        Instruction::Acc(_) => None,
     }
-    (new_program.len(), new_program)
+}
+
+fn repair(program: &[Instruction]) -> Option<(usize, i64)> {
+    let len = program.len();
+
+    // Reverse the normal successor edges and walk backwards from the terminate
+    // node (index == len) to find every index from which ordinary execution
+    // would eventually halt.
+    let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); len + 1];
+    for (i, instruction) in program.iter().enumerate() {
+        if let Some(successor) = normal_successor(i, instruction, len) {
+            predecessors[successor].push(i);
+        }
+    }
+    let mut can_reach_end = vec![false; len + 1];
+    can_reach_end[len] = true;
+    let mut stack = vec![len];
+    while let Some(node) = stack.pop() {
+        for &predecessor in &predecessors[node] {
+            if !can_reach_end[predecessor] {
+                can_reach_end[predecessor] = true;
+                stack.push(predecessor);
+            }
+        }
+    }
+
+    // Trace the real forward run and look for the first jmp/nop whose flipped
+    // successor would drop us onto an index that reaches the end.
+    let mut program_counter = 0;
+    let mut executed: HashSet<usize> = HashSet::new();
+    while program_counter < len {
+        if !executed.insert(program_counter) {
+            return None;
+        }
+        let instruction = &program[program_counter];
+        if !matches!(instruction, Instruction::Acc(_)) {
+            if let Some(successor) = flipped_successor(program_counter, instruction, len) {
+                if can_reach_end[successor] {
+                    let patched = PatchedProgram {
+                        program,
+                        override_index: Some(program_counter),
+                    };
+                    let accumulator = Computer::new()
+                        .execute(&patched)
+                        .expect("repaired program should halt");
+                    return Some((program_counter, accumulator));
+                }
+            }
+        }
+        program_counter = match normal_successor(program_counter, instruction, len) {
+            Some(next) => next,
+            None => return None,
+        };
+    }
+    None
 }
 
 struct Computer {
     program_counter: usize,
     accumulator: i64,
+    visited: HashSet<usize>,
 }
 
 impl Computer {
@@ -45,31 +196,147 @@ impl Computer {
         Computer{
             program_counter: 0,
             accumulator: 0,
+            visited: HashSet::new(),
         }
     }
 
-    fn execute(&mut self, program: &[Instruction]) -> result::Result<i64, i64> {
+    fn execute<P: InstructionSource + ?Sized>(&mut self, program: &P) -> result::Result<i64, ExecError> {
         self.program_counter = 0;
         self.accumulator = 0;
         let mut executed: HashSet<usize> = HashSet::new();
         while self.program_counter < program.len() {
             if executed.contains(&self.program_counter) {
-                return Err(self.accumulator)
+                return Err(ExecError::InfiniteLoop { accumulator: self.accumulator });
             }
             executed.insert(self.program_counter);
-            let instruction = program.get(self.program_counter).expect("instruction out of range");
+            let instruction = program.get(self.program_counter);
             match instruction {
                 Instruction::Nop(_) => self.program_counter += 1,
                 Instruction::Acc(n) => {
-                    self.accumulator += *n;
+                    self.accumulator += n;
                     self.program_counter += 1;
                 }
-                Instruction::Jmp(n) => self.program_counter = (self.program_counter as i64 + *n) as usize,
+                Instruction::Jmp(n) => {
+                    let target = self.program_counter as i64 + n;
+                    if target < 0 || target as usize > program.len() {
+                        return Err(ExecError::SegmentationFault { pc: self.program_counter });
+                    }
+                    self.program_counter = target as usize;
+                }
             }
 
         }
         Ok(self.accumulator)
     }
+
+    /// Run the program cycle by cycle, advancing a global cycle counter, and sum
+    /// the "signal strength" (cycle number × accumulator) at every sample point.
+    /// Stops on normal termination or when a loop is detected.
+    fn run_cycles(&mut self, program: &[Instruction], samples: &[usize]) -> i64 {
+        self.program_counter = 0;
+        self.accumulator = 0;
+        let mut cycle = 0usize;
+        let mut signal = 0;
+        let mut executed: HashSet<usize> = HashSet::new();
+        while self.program_counter < program.len() {
+            if !executed.insert(self.program_counter) {
+                break;
+            }
+            let instruction = program[self.program_counter];
+            for _ in 0..instruction.cycles() {
+                cycle += 1;
+                if samples.contains(&cycle) {
+                    signal += cycle as i64 * self.accumulator;
+                }
+            }
+            match instruction {
+                Instruction::Nop(_) => self.program_counter += 1,
+                Instruction::Acc(n) => {
+                    self.accumulator += n;
+                    self.program_counter += 1;
+                }
+                Instruction::Jmp(n) => self.program_counter = (self.program_counter as i64 + n) as usize,
+            }
+        }
+        signal
+    }
+
+    /// Draw the accumulator as a three-pixel sprite sweeping across a fixed-width
+    /// row: one character per cycle, lit when the sprite covers the beam, flushing
+    /// a newline at every row boundary.
+    fn render(&mut self, program: &[Instruction], width: usize) -> String {
+        self.program_counter = 0;
+        self.accumulator = 0;
+        let mut cycle = 0usize;
+        let mut output = String::new();
+        let mut executed: HashSet<usize> = HashSet::new();
+        while self.program_counter < program.len() {
+            if !executed.insert(self.program_counter) {
+                break;
+            }
+            let instruction = program[self.program_counter];
+            for _ in 0..instruction.cycles() {
+                let position = (cycle % width) as i64;
+                if (position - self.accumulator).abs() <= 1 {
+                    output.push('#');
+                } else {
+                    output.push('.');
+                }
+                cycle += 1;
+                if cycle % width == 0 {
+                    output.push('\n');
+                }
+            }
+            match instruction {
+                Instruction::Nop(_) => self.program_counter += 1,
+                Instruction::Acc(n) => {
+                    self.accumulator += n;
+                    self.program_counter += 1;
+                }
+                Instruction::Jmp(n) => self.program_counter = (self.program_counter as i64 + n) as usize,
+            }
+        }
+        output
+    }
+
+    /// Execute exactly one instruction, reporting whether the machine should
+    /// continue, has halted, or has re-entered an already-visited instruction.
+    fn step(&mut self, program: &[Instruction]) -> StepOutcome {
+        self.step_with(program, |_| {})
+    }
+
+    /// Like [`Computer::step`], but invoke `on_step` with a [`TraceRecord`] for
+    /// the executed instruction — useful for disassembly traces or PC breakpoints.
+    fn step_with<F: FnMut(&TraceRecord)>(&mut self, program: &[Instruction], mut on_step: F) -> StepOutcome {
+        let pc_before = self.program_counter;
+        if pc_before >= program.len() {
+            return StepOutcome::Halt(self.accumulator);
+        }
+        if self.visited.contains(&pc_before) {
+            return StepOutcome::LoopDetected(self.accumulator);
+        }
+        self.visited.insert(pc_before);
+        let instruction = program[pc_before];
+        let accumulator_before = self.accumulator;
+        let pc_after = match instruction {
+            Instruction::Nop(_) => pc_before + 1,
+            Instruction::Acc(n) => {
+                self.accumulator += n;
+                pc_before + 1
+            }
+            Instruction::Jmp(n) => (pc_before as i64 + n) as usize,
+        };
+        self.program_counter = pc_after;
+        let record = TraceRecord {
+            pc_before,
+            pc_after,
+            instruction,
+            accumulator_delta: self.accumulator - accumulator_before,
+            target_visited: self.visited.contains(&pc_after),
+        };
+        on_step(&record);
+        StepOutcome::Continue
+    }
 }
 
 fn read_lines<P: AsRef<path::Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<fs::File>>> {
@@ -77,49 +344,40 @@ fn read_lines<P: AsRef<path::Path>>(filename: P) -> io::Result<io::Lines<io::Buf
     Ok(io::BufReader::new(file).lines())
 }
 
-fn read_all_instructions(lines: impl Iterator<Item=io::Result<String>>) -> Program {
+fn read_all_instructions(lines: impl Iterator<Item=io::Result<String>>) -> result::Result<Program, ParseError> {
     let mut all: Vec<Instruction> = Vec::new();
     for line_res in lines {
         let line = line_res.expect("invalid string");
-        all.push(parse_instruction(&line));
+        all.push(parse_instruction(&line)?);
     }
-    all
+    Ok(all)
 }
 
-fn parse_instruction(code: &str) -> Instruction {
-    let caps = INSTRUCTION_REGEX.captures(code).expect("invalid code");
-    let argument = caps["arg"].parse::<i64>().expect("invalid argument");
+fn parse_instruction(code: &str) -> result::Result<Instruction, ParseError> {
+    let caps = INSTRUCTION_REGEX.captures(code).ok_or_else(|| ParseError::InvalidInstruction(code.to_string()))?;
+    let argument = caps["arg"].parse::<i64>().map_err(|_| ParseError::InvalidArgument(caps["arg"].to_string()))?;
     match &caps["opcode"] {
-        "nop" => Instruction::Nop(argument),
-        "acc" => Instruction::Acc(argument),
-        "jmp" => Instruction::Jmp(argument),
-        opcode => panic!("invalid opcode >>{}<<", opcode.to_string()),
+        "nop" => Ok(Instruction::Nop(argument)),
+        "acc" => Ok(Instruction::Acc(argument)),
+        "jmp" => Ok(Instruction::Jmp(argument)),
+        opcode => Err(ParseError::InvalidInstruction(opcode.to_string())),
     }
 }
 
 fn main() {
     let lines = read_lines("input.txt").expect("error reading input");
-    let program = read_all_instructions(lines);
+    let program = read_all_instructions(lines).expect("error parsing input");
     let mut computer = Computer::new();
-    let result = computer.execute(&program);
-    if let Err(accumulator) = result {
+    let result = computer.execute(&program[..]);
+    if let Err(ExecError::InfiniteLoop { accumulator }) = result {
         println!("The accumulator before looping is {}", accumulator);
+    } else if let Err(err) = result {
+        panic!("execution failed: {:?}", err);
     } else {
         panic!("returned OK");
     }
 
-    let (mut n, mut new_program) = flip_after(&program, 0);
-    let mut new_result = computer.execute(&new_program);
-    while new_result.is_err() {
-        let flipped = flip_after(&program, n);
-        n = flipped.0;
-        new_program = flipped.1;
-        if n >= new_program.len() {
-            panic!("cannot find valid program");
-        }
-        new_result = computer.execute(&new_program);
-    }
-    let success = new_result.unwrap();
+    let (_, success) = repair(&program).expect("cannot find valid program");
     println!("The final result is {}", success);
 }
 
@@ -146,26 +404,74 @@ mod tests {
     #[test]
     fn can_parse_an_instruction() {
         let nop = parse_instruction("nop +0");
-        assert!(matches!(nop, Instruction::Nop));
+        assert!(matches!(nop, Ok(Instruction::Nop(0))));
 
         let acc = parse_instruction("acc +1");
-        assert!(matches!(acc, Instruction::Acc(1)));
+        assert!(matches!(acc, Ok(Instruction::Acc(1))));
 
         let jmp = parse_instruction("jmp +4");
-        assert!(matches!(jmp, Instruction::Jmp(4)));
+        assert!(matches!(jmp, Ok(Instruction::Jmp(4))));
+    }
+
+    #[test]
+    fn reports_an_error_on_a_malformed_instruction() {
+        assert!(matches!(parse_instruction("wat +0"), Err(ParseError::InvalidInstruction(_))));
     }
 
     #[test]
     fn can_read_all_instructions() {
         let code = to_line_results(TEST_INSTRUCTIONS);
-        let instructions = read_all_instructions(code);
+        let instructions = read_all_instructions(code).expect("valid instructions");
         assert_eq!(9, instructions.len());
     }
 
     #[test]
     fn returns_the_correct_error_on_loop_detection() {
-        let program = read_all_instructions(to_line_results(TEST_INSTRUCTIONS));
+        let program = read_all_instructions(to_line_results(TEST_INSTRUCTIONS)).expect("valid instructions");
+        let mut computer = Computer::new();
+        assert!(matches!(computer.execute(&program[..]), Err(ExecError::InfiniteLoop { accumulator: 5 })));
+    }
+
+    #[test]
+    fn repairs_the_program_in_linear_time() {
+        let program = read_all_instructions(to_line_results(TEST_INSTRUCTIONS)).expect("valid instructions");
+        assert!(matches!(repair(&program), Some((7, 8))));
+    }
+
+    const SPRITE_PROGRAM: &'static str = indoc!{"\
+        acc +1
+        nop +0"};
+
+    #[test]
+    fn sums_the_signal_strength_at_sample_points() {
+        let program = read_all_instructions(to_line_results(SPRITE_PROGRAM)).expect("valid instructions");
+        let mut computer = Computer::new();
+        // The accumulator is 0 for the two cycles of `acc`, then 1 for the nop.
+        assert_eq!(3, computer.run_cycles(&program, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn renders_the_sprite_across_a_row() {
+        let program = read_all_instructions(to_line_results(SPRITE_PROGRAM)).expect("valid instructions");
         let mut computer = Computer::new();
-        assert!(matches!(computer.execute(program), Err(5)));
+        assert_eq!("###", computer.render(&program, 40));
+    }
+
+    #[test]
+    fn steps_until_a_loop_is_detected_and_traces_each_step() {
+        let program = read_all_instructions(to_line_results(TEST_INSTRUCTIONS)).expect("valid instructions");
+        let mut computer = Computer::new();
+        let mut steps = 0;
+        loop {
+            match computer.step_with(&program, |_| steps += 1) {
+                StepOutcome::Continue => (),
+                StepOutcome::LoopDetected(accumulator) => {
+                    assert_eq!(5, accumulator);
+                    break;
+                }
+                StepOutcome::Halt(_) => panic!("program should loop, not halt"),
+            }
+        }
+        assert_eq!(7, steps);
     }
 }